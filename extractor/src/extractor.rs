@@ -4,21 +4,66 @@ use std::collections::BTreeMap as Map;
 use std::collections::BTreeSet as Set;
 use std::fmt;
 use std::path::Path;
-use tree_sitter::{Language, Node, Parser, Tree};
+use std::rc::Rc;
+use tree_sitter::{InputEdit, Language, Node, Parser, Point, Tree};
 
 pub struct Extractor {
     pub parser: Parser,
     pub schema: Vec<Entry>,
+    /// The tree from the previous call to `extract_incremental`, per path, together with
+    /// the extraction state needed to keep labels stable across incremental re-extractions.
+    trees: Map<String, CachedTree>,
+    /// When set, tree-sitter "extra" nodes (comments, whitespace, ...) are captured into a
+    /// synthesized `comments` table instead of being silently dropped. Off by default so
+    /// consumers whose dbscheme doesn't model trivia are unaffected.
+    pub extract_trivia: bool,
+}
+
+/// Everything that needs to survive between two `extract_incremental` calls for the same
+/// path so that unchanged nodes keep the same label.
+struct CachedTree {
+    tree: Tree,
+    counter: i32,
+    labels: Map<(usize, usize, String), Label>,
+}
+
+/// A single tree-sitter edit to apply to a previously parsed tree before reparsing, as
+/// produced by an editor or watch-mode diff between the old and new source text.
+pub struct Edit {
+    pub start_byte: usize,
+    pub old_end_byte: usize,
+    pub new_end_byte: usize,
+    pub start_position: Point,
+    pub old_end_position: Point,
+    pub new_end_position: Point,
+}
+
+impl Edit {
+    fn to_input_edit(&self) -> InputEdit {
+        InputEdit {
+            start_byte: self.start_byte,
+            old_end_byte: self.old_end_byte,
+            new_end_byte: self.new_end_byte,
+            start_position: self.start_position,
+            old_end_position: self.old_end_position,
+            new_end_position: self.new_end_position,
+        }
+    }
 }
 
 pub fn create(language: Language, schema: Vec<Entry>) -> Extractor {
     let mut parser = Parser::new();
     parser.set_language(language).unwrap();
 
-    Extractor { parser, schema }
+    Extractor {
+        parser,
+        schema,
+        trees: Map::new(),
+        extract_trivia: false,
+    }
 }
 impl Extractor {
-    pub fn extract<'a>(&'a mut self, path: &Path) -> std::io::Result<Program> {
+    pub fn extract<'a>(&'a mut self, path: &Path) -> std::io::Result<(Program, Vec<Diagnostic>)> {
         let source = std::fs::read(&path)?;
         let tree = &self
             .parser
@@ -26,21 +71,158 @@ impl Extractor {
             .expect("Failed to parse file");
         let mut visitor = Visitor {
             source: &source,
-            trap_output: vec![TrapEntry::Comment(format!(
+            trap_output: TrapSink::Buffer(vec![TrapEntry::Comment(format!(
                 "Auto-generated TRAP file for {}",
                 path.display()
-            ))],
+            ))]),
             counter: -1,
             // TODO: should we handle path strings that are not valid UTF8 better?
             path: format!("{}", path.display()),
             stack: Vec::new(),
             tables: build_schema_lookup(&self.schema),
             union_types: build_union_type_lookup(&self.schema),
+            changed_ranges: None,
+            incremental: false,
+            label_cache: Map::new(),
+            interner: StringInterner::new(true),
+            capture_extras: self.extract_trivia,
+            trivia_stack: Vec::new(),
+            diagnostics: Vec::new(),
+        };
+        traverse(&tree, &mut visitor)?;
+
+        &self.parser.reset();
+        Ok((Program(visitor.trap_output.into_buffer()), visitor.diagnostics))
+    }
+
+    /// Extracts `path`, writing each `TrapEntry` to `out` as soon as the node that produced it
+    /// closes, instead of buffering the whole program in memory. This keeps peak memory roughly
+    /// constant regardless of file size, at the cost of not being able to incrementally re-parse
+    /// (unlike `extract_incremental`, this always does a fresh `parser.parse`).
+    pub fn extract_streaming<W: std::io::Write>(
+        &mut self,
+        path: &Path,
+        mut out: W,
+    ) -> std::io::Result<Vec<Diagnostic>> {
+        let source = std::fs::read(&path)?;
+        let tree = self
+            .parser
+            .parse(&source, None)
+            .expect("Failed to parse file");
+        let mut visitor = Visitor {
+            source: &source,
+            trap_output: TrapSink::Stream(&mut out),
+            counter: -1,
+            path: format!("{}", path.display()),
+            stack: Vec::new(),
+            tables: build_schema_lookup(&self.schema),
+            union_types: build_union_type_lookup(&self.schema),
+            changed_ranges: None,
+            incremental: false,
+            label_cache: Map::new(),
+            interner: StringInterner::new(false),
+            capture_extras: self.extract_trivia,
+            trivia_stack: Vec::new(),
+            diagnostics: Vec::new(),
         };
-        traverse(&tree, &mut visitor);
+        visitor.trap_output.push(TrapEntry::Comment(format!(
+            "Auto-generated TRAP file for {}",
+            path.display()
+        )))?;
+        traverse(&tree, &mut visitor)?;
 
         &self.parser.reset();
-        Ok(Program(visitor.trap_output))
+        Ok(visitor.diagnostics)
+    }
+
+    /// Re-extracts `path` reusing the tree produced by the previous call to
+    /// `extract_incremental` for the same path, if any (`extract` keeps no tree, so a path
+    /// only ever passed to `extract` gets a full parse here). `edits` are applied to that old tree
+    /// (via `tree_sitter::Tree::edit`) before tree-sitter reparses the new source, so the
+    /// parser can reuse unaffected subtrees instead of re-walking the whole file. Afterwards,
+    /// `Tree::changed_ranges` tells us which byte ranges actually changed; nodes outside all
+    /// changed ranges reuse the label they were assigned on the previous run (keyed by their
+    /// byte range and kind) instead of bumping `self.counter`, so `self.counter` only advances
+    /// for nodes that are new or have genuinely changed. Since an edit shifts the byte range of
+    /// every node that comes after it, the cached labels are remapped through `edits` (the same
+    /// deltas just given to `Tree::edit`) before being looked up against the new tree, or an
+    /// unchanged node past the first edit would simply never hit the cache.
+    ///
+    /// The returned `Program` is a *delta*: an unchanged node whose label is reused emits no
+    /// `New`/`Definition` row, only a fresh `Located` row at its current position (plus a
+    /// `comments_enclosing` row for each captured trivia node it encloses) (`changed_ranges`
+    /// only reports structural changes, so a node can keep its old label while still having moved
+    /// because an edit shifted everything after it). The delta therefore references labels that
+    /// are only ever defined in a previous call's output, and may re-assert a `Located` row for a
+    /// label a previous call already defined. Callers must merge each `Program` into the
+    /// accumulated TRAP for `path` - letting a later `Located` row for a label supersede an
+    /// earlier one - rather than treating it as self-contained. Note that `interner` is rebuilt
+    /// fresh per call, so string *sharing* doesn't carry over between calls - only label identity
+    /// (via `label_cache`/`CachedTree::labels`) does.
+    pub fn extract_incremental(
+        &mut self,
+        path: &Path,
+        edits: &[Edit],
+    ) -> std::io::Result<(Program, Vec<Diagnostic>)> {
+        let source = std::fs::read(&path)?;
+        let path_str = format!("{}", path.display());
+
+        let (new_tree, changed_ranges, counter, label_cache) = match self.trees.remove(&path_str) {
+            Some(mut cached) => {
+                for edit in edits {
+                    cached.tree.edit(&edit.to_input_edit());
+                }
+                let new_tree = self
+                    .parser
+                    .parse(&source, Some(&cached.tree))
+                    .expect("Failed to parse file");
+                let changed_ranges: Vec<_> = cached.tree.changed_ranges(&new_tree).collect();
+                let labels = remap_label_cache(cached.labels, edits);
+                (new_tree, Some(changed_ranges), cached.counter, labels)
+            }
+            None => {
+                let new_tree = self
+                    .parser
+                    .parse(&source, None)
+                    .expect("Failed to parse file");
+                (new_tree, None, -1, Map::new())
+            }
+        };
+
+        let mut visitor = Visitor {
+            source: &source,
+            trap_output: TrapSink::Buffer(vec![TrapEntry::Comment(format!(
+                "Auto-generated TRAP file for {}",
+                path.display()
+            ))]),
+            counter,
+            path: path_str.clone(),
+            stack: Vec::new(),
+            tables: build_schema_lookup(&self.schema),
+            union_types: build_union_type_lookup(&self.schema),
+            changed_ranges,
+            incremental: true,
+            label_cache,
+            interner: StringInterner::new(true),
+            capture_extras: self.extract_trivia,
+            trivia_stack: Vec::new(),
+            diagnostics: Vec::new(),
+        };
+        traverse(&new_tree, &mut visitor)?;
+
+        &self.parser.reset();
+        self.trees.insert(
+            path_str,
+            CachedTree {
+                tree: new_tree,
+                counter: visitor.counter,
+                labels: visitor.label_cache,
+            },
+        );
+        Ok((
+            Program(visitor.trap_output.into_buffer()),
+            visitor.diagnostics,
+        ))
     }
 }
 
@@ -69,8 +251,9 @@ struct Visitor<'a> {
     path: String,
     /// The source code as a UTF-8 byte array
     source: &'a Vec<u8>,
-    /// The accumulated trap entries
-    trap_output: Vec<TrapEntry>,
+    /// Where trap entries go as they are produced: buffered in memory, or written straight
+    /// through to a `Write` for constant-memory extraction.
+    trap_output: TrapSink<'a>,
     /// A counter for generating fresh labels
     counter: i32,
     /// A lookup table from type name to dbscheme table entries
@@ -83,81 +266,257 @@ struct Visitor<'a> {
     /// from the stack and matched against the dbscheme for the node. If the expectations are met
     /// the corresponding row definitions are added to the trap_output.
     stack: Vec<Vec<(Option<&'static str>, Label, TypeName)>>,
+    /// The byte ranges that changed since the previous incremental extraction, as reported by
+    /// `Tree::changed_ranges`. `None` means this is a full (non-incremental) extraction, so
+    /// every node is treated as changed.
+    changed_ranges: Option<Vec<tree_sitter::Range>>,
+    /// Whether this traversal is on the `extract_incremental` path, i.e. whether `label_cache`
+    /// is worth populating at all. `extract`/`extract_streaming` never read a previous run's
+    /// labels back, so filling in `label_cache` for them would just grow it by one entry per
+    /// node for no benefit - the opposite of `extract_streaming`'s constant-memory promise.
+    incremental: bool,
+    /// Maps a node's (start_byte, end_byte, kind) to the label it was assigned, so that an
+    /// unchanged node reuses its previous label instead of the visitor allocating a fresh one.
+    /// When reused across `extract_incremental` calls, the keys must first be remapped through
+    /// `remap_label_cache`/`shift_byte`, since edits shift the byte range of every following
+    /// node between one call and the next. Only populated when `incremental` is set.
+    label_cache: Map<(usize, usize, String), Label>,
+    /// Deduplicates leaf source-slice strings (and trivia kinds) so that the same text appearing
+    /// at many nodes (a repeated keyword, identifier, etc.) shares one allocation. Table names
+    /// are interned too, but each `Definition` row still takes its own `String` copy. This is a
+    /// string-sharing cache only: every leaf node still gets its own label, location and
+    /// `Definition` row, since two occurrences of the same text are still distinct nodes with
+    /// distinct locations.
+    interner: StringInterner,
+    /// Whether tree-sitter "extra" nodes (comments, whitespace, ...) are captured into the
+    /// synthesized `comments` table instead of being dropped. Mirrors `Extractor::extract_trivia`.
+    capture_extras: bool,
+    /// Parallel to `stack`: the trivia nodes captured while the corresponding named node was
+    /// open, flushed into `TriviaOf` associations once that node closes.
+    trivia_stack: Vec<Vec<Label>>,
+    /// Problems noticed while walking the tree, collected instead of printed so that
+    /// extraction can continue past them and callers can consume them programmatically.
+    diagnostics: Vec<Diagnostic>,
+}
+
+/// Interns strings so that repeated occurrences of the same text share one allocation. Disabled
+/// for `extract_streaming`: there every row is written out and dropped as soon as it's produced,
+/// so the cache would just retain every distinct string for the life of the run in exchange for
+/// no sharing benefit, the opposite of that extraction mode's constant-memory promise.
+///
+/// This is deliberately *text* sharing only, not *label* sharing: an earlier version of this
+/// cache was keyed on `(table_name, text)` and handed out one `@label` per repeated token, which
+/// collapsed distinct leaf nodes with identical text onto a single label and corrupted their
+/// `_def` relation (each occurrence has its own location and needs its own label). That dedup
+/// was unsound and was removed; reusing one allocation for the underlying `String` is the part
+/// of the idea that's actually safe to keep.
+struct StringInterner {
+    cache: Map<String, Rc<str>>,
+    enabled: bool,
+}
+
+impl StringInterner {
+    fn new(enabled: bool) -> StringInterner {
+        StringInterner {
+            cache: Map::new(),
+            enabled,
+        }
+    }
+
+    fn intern(&mut self, s: &str) -> Rc<str> {
+        if !self.enabled {
+            return Rc::from(s);
+        }
+        if let Some(existing) = self.cache.get(s) {
+            return existing.clone();
+        }
+        let interned: Rc<str> = Rc::from(s);
+        self.cache.insert(s.to_owned(), interned.clone());
+        interned
+    }
 }
 
 impl Visitor<'_> {
-    fn enter_node(&mut self, node: Node) -> bool {
+    fn enter_node(&mut self, node: Node) -> std::io::Result<bool> {
         if node.is_error() {
-            println!(
-                "error: {}:{}: parse error",
-                &self.path,
+            self.diagnostic(
                 node.start_position().row,
+                DiagnosticKind::ParseError,
+                "parse error".to_owned(),
             );
-            return false;
+            return Ok(false);
         }
         if node.is_missing() {
-            println!(
-                "error: {}:{}: parse error: expecting '{}'",
-                &self.path,
+            self.diagnostic(
                 node.start_position().row,
-                node.kind()
+                DiagnosticKind::MissingNode,
+                format!("parse error: expecting '{}'", node.kind()),
             );
-            return false;
+            return Ok(false);
         }
 
         if node.is_extra() {
-            return false;
+            if self.capture_extras {
+                self.capture_trivia(node)?;
+            }
+            return Ok(false);
         }
 
         self.stack.push(Vec::new());
-        return true;
+        self.trivia_stack.push(Vec::new());
+        Ok(true)
     }
 
-    fn leave_node(&mut self, field_name: Option<&'static str>, node: Node) {
+    fn leave_node(&mut self, field_name: Option<&'static str>, node: Node) -> std::io::Result<()> {
         if node.is_extra() || node.is_error() || node.is_missing() {
-            return;
+            return Ok(());
         }
         let child_nodes = self.stack.pop().expect("Vistor: empty stack");
-        let table = self.tables.get(&TypeName {
+        let child_trivia = self.trivia_stack.pop().expect("Vistor: empty trivia stack");
+        let type_name = TypeName {
             kind: node.kind().to_owned(),
             named: node.is_named(),
-        });
+        };
+        let table = self.tables.get(&type_name);
         if let Some(Entry::Table { fields, .. }) = table {
+            if self.incremental && !self.node_is_changed(&node) {
+                if let Some(&id) = self.label_cache.get(&node_key(&node)) {
+                    // `changed_ranges` only reports structural changes, not position shifts:
+                    // a node left untouched by the edit itself can still have moved because
+                    // text was inserted or deleted somewhere above it. Re-emit `Located` with
+                    // the node's current position so the merged TRAP doesn't keep a stale
+                    // row/column for a label whose source moved out from under it.
+                    self.trap_output
+                        .push(location_for(&self.path, id.location(), node))?;
+                    self.attach_trivia(id, child_trivia)?;
+                    if let Some(parent) = self.stack.last_mut() {
+                        parent.push((field_name, id, type_name));
+                    };
+                    return Ok(());
+                }
+            }
+            let table_name = self.interner.intern(&node_type_name(node.kind(), node.is_named()));
+
+            if fields.is_empty() {
+                let text = sliced_source_text(self.source, node);
+                let text = self.interner.intern(text);
+                self.counter += 1;
+                let id = Label::Normal(self.counter);
+                let loc = Label::Location(self.counter);
+                self.trap_output.push(TrapEntry::New(id))?;
+                self.trap_output.push(TrapEntry::New(loc))?;
+                self.trap_output.push(location_for(&self.path, loc, node))?;
+                self.trap_output.push(TrapEntry::Definition(
+                    table_name.to_string(),
+                    id,
+                    vec![Arg::String(text)],
+                    loc,
+                ))?;
+                if self.incremental {
+                    self.label_cache.insert(node_key(&node), id);
+                }
+                self.attach_trivia(id, child_trivia)?;
+                if let Some(parent) = self.stack.last_mut() {
+                    parent.push((field_name, id, type_name))
+                };
+                return Ok(());
+            }
+
             self.counter += 1;
             let id = Label::Normal(self.counter);
             let loc = Label::Location(self.counter);
-            self.trap_output.push(TrapEntry::New(id));
-            self.trap_output.push(TrapEntry::New(loc));
-            self.trap_output.push(location_for(&self.path, loc, node));
-            let table_name = node_type_name(node.kind(), node.is_named());
-            let args: Option<Vec<Arg>>;
-            if fields.is_empty() {
-                args = Some(vec![sliced_source_arg(self.source, node)]);
-            } else {
-                args = self.complex_node(&node, fields, child_nodes, id);
+            if self.incremental {
+                self.label_cache.insert(node_key(&node), id);
             }
-            if let Some(args) = args {
+            self.trap_output.push(TrapEntry::New(id))?;
+            self.trap_output.push(TrapEntry::New(loc))?;
+            self.trap_output.push(location_for(&self.path, loc, node))?;
+            if let Some(args) = self.complex_node(&node, fields, child_nodes, id)? {
                 self.trap_output
-                    .push(TrapEntry::Definition(table_name, id, args, loc));
+                    .push(TrapEntry::Definition(table_name.to_string(), id, args, loc))?;
             }
+            self.attach_trivia(id, child_trivia)?;
             if let Some(parent) = self.stack.last_mut() {
-                parent.push((
-                    field_name,
-                    id,
-                    TypeName {
-                        kind: node.kind().to_owned(),
-                        named: node.is_named(),
-                    },
-                ))
+                parent.push((field_name, id, type_name))
             };
         } else {
-            println!(
-                "error: {}:{}: unknown table type: '{}'",
-                &self.path,
+            self.diagnostic(
                 node.start_position().row,
-                node.kind()
+                DiagnosticKind::UnknownTableType,
+                format!("unknown table type: '{}'", node.kind()),
             );
         }
+        Ok(())
+    }
+
+    /// Associates each trivia node collected while `id`'s node was open with `id`, the
+    /// nearest enclosing named node, so downstream queries can attach a comment (or other
+    /// extra node) to the declaration it documents.
+    fn attach_trivia(&mut self, id: Label, child_trivia: Vec<Label>) -> std::io::Result<()> {
+        for trivia_id in child_trivia {
+            self.trap_output
+                .push(TrapEntry::TriviaOf(trivia_id, id))?;
+        }
+        Ok(())
+    }
+
+    /// Captures a tree-sitter "extra" node (comments, whitespace, ...) that would otherwise be
+    /// silently dropped, emitting it into a synthesized `comments` table and recording it so
+    /// it can be attached to its nearest enclosing named node once that node closes.
+    fn capture_trivia(&mut self, node: Node) -> std::io::Result<()> {
+        if self.incremental && !self.node_is_changed(&node) {
+            if let Some(&id) = self.label_cache.get(&node_key(&node)) {
+                self.trap_output
+                    .push(location_for(&self.path, id.location(), node))?;
+                if let Some(parent_trivia) = self.trivia_stack.last_mut() {
+                    parent_trivia.push(id);
+                }
+                return Ok(());
+            }
+        }
+        self.counter += 1;
+        let id = Label::Normal(self.counter);
+        let loc = Label::Location(self.counter);
+        if self.incremental {
+            self.label_cache.insert(node_key(&node), id);
+        }
+        self.trap_output.push(TrapEntry::New(id))?;
+        self.trap_output.push(TrapEntry::New(loc))?;
+        self.trap_output.push(location_for(&self.path, loc, node))?;
+        let kind = self.interner.intern(node.kind());
+        let text = self.interner.intern(sliced_source_text(self.source, node));
+        self.trap_output.push(TrapEntry::Definition(
+            "comments".to_owned(),
+            id,
+            vec![Arg::String(kind), Arg::String(text)],
+            loc,
+        ))?;
+        if let Some(parent_trivia) = self.trivia_stack.last_mut() {
+            parent_trivia.push(id);
+        }
+        Ok(())
+    }
+
+    /// Records a diagnostic at `row` instead of printing it, so extraction can continue.
+    fn diagnostic(&mut self, row: usize, kind: DiagnosticKind, message: String) {
+        self.diagnostics.push(Diagnostic {
+            severity: kind.severity(),
+            path: self.path.clone(),
+            row,
+            kind,
+            message,
+        });
+    }
+
+    /// Whether `node`'s byte range overlaps any range that changed since the previous
+    /// incremental extraction. Always true for a full (non-incremental) extraction.
+    fn node_is_changed(&self, node: &Node) -> bool {
+        match &self.changed_ranges {
+            None => true,
+            Some(ranges) => ranges
+                .iter()
+                .any(|r| r.start_byte < node.end_byte() && node.start_byte() < r.end_byte),
+        }
     }
     fn complex_node(
         &mut self,
@@ -165,7 +524,7 @@ impl Visitor<'_> {
         fields: &Vec<Field>,
         child_nodes: Vec<(Option<&str>, Label, TypeName)>,
         parent_id: Label,
-    ) -> Option<Vec<Arg>> {
+    ) -> std::io::Result<Option<Vec<Arg>>> {
         let mut map: Map<&Option<String>, (&Field, Vec<Label>)> = std::collections::BTreeMap::new();
         for field in fields {
             map.insert(&field.name, (field, Vec::new()));
@@ -176,25 +535,29 @@ impl Visitor<'_> {
                 if self.type_matches(&child_type, &field.types) {
                     values.push(child_id);
                 } else if field.name.is_some() {
-                    println!(
-                        "error: {}:{}: type mismatch for field {}::{} with type {:?} != {:?}",
-                        &self.path,
+                    self.diagnostic(
                         node.start_position().row,
-                        node.kind(),
-                        child_field.unwrap_or("child"),
-                        child_type,
-                        field.types
+                        DiagnosticKind::TypeMismatch,
+                        format!(
+                            "type mismatch for field {}::{} with type {:?} != {:?}",
+                            node.kind(),
+                            child_field.unwrap_or("child"),
+                            child_type,
+                            field.types
+                        ),
                     )
                 }
             } else {
                 if child_field.is_some() || child_type.named {
-                    println!(
-                        "error: {}:{}: value for unknown field: {}::{} and type {:?}",
-                        &self.path,
+                    self.diagnostic(
                         node.start_position().row,
-                        node.kind(),
-                        &child_field.unwrap_or("child"),
-                        &child_type
+                        DiagnosticKind::UnknownField,
+                        format!(
+                            "value for unknown field: {}::{} and type {:?}",
+                            node.kind(),
+                            &child_field.unwrap_or("child"),
+                            &child_type
+                        ),
                     );
                 }
             }
@@ -209,21 +572,27 @@ impl Visitor<'_> {
                         args.push(Arg::Label(*child_ids.first().unwrap()));
                     } else {
                         is_valid = false;
-                        println!(
-                            "error: {}:{}: {} for field: {}::{}",
-                            &self.path,
-                            node.start_position().row,
-                            if child_ids.is_empty() {
-                                "missing value"
-                            } else {
-                                "too many values"
-                            },
-                            node.kind(),
-                            match field.name.as_ref() {
-                                Some(x) => x,
-                                None => "child",
-                            }
-                        )
+                        let field_name = match field.name.as_ref() {
+                            Some(x) => x.as_str(),
+                            None => "child",
+                        };
+                        if child_ids.is_empty() {
+                            self.diagnostic(
+                                node.start_position().row,
+                                DiagnosticKind::MissingFieldValue,
+                                format!("missing value for field: {}::{}", node.kind(), field_name),
+                            )
+                        } else {
+                            self.diagnostic(
+                                node.start_position().row,
+                                DiagnosticKind::TooManyFieldValues,
+                                format!(
+                                    "too many values for field: {}::{}",
+                                    node.kind(),
+                                    field_name
+                                ),
+                            )
+                        }
                     }
                 }
                 Storage::Table { parent, index } => {
@@ -237,15 +606,15 @@ impl Visitor<'_> {
                             },
                             Index(*index),
                             *child_id,
-                        ));
+                        ))?;
                     }
                 }
             }
         }
         if is_valid {
-            Some(args)
+            Ok(Some(args))
         } else {
-            None
+            Ok(None)
         }
     }
     fn type_matches(&self, tp: &TypeName, types: &Set<TypeName>) -> bool {
@@ -263,12 +632,52 @@ impl Visitor<'_> {
     }
 }
 
+/// Remaps every key in a `label_cache` carried over from the previous `extract_incremental`
+/// call through `edits`, so that a byte range recorded against the old source lines up with
+/// where that same unchanged text now lives in the new source. Without this, only the prefix
+/// of the file before the first edit would ever hit the cache: every node after it keeps its
+/// old byte range as its key, which no longer matches any node in the newly parsed tree.
+fn remap_label_cache(
+    labels: Map<(usize, usize, String), Label>,
+    edits: &[Edit],
+) -> Map<(usize, usize, String), Label> {
+    labels
+        .into_iter()
+        .map(|((start, end, kind), label)| {
+            let (start, end) = edits.iter().fold((start, end), |(start, end), edit| {
+                (shift_byte(start, edit), shift_byte(end, edit))
+            });
+            ((start, end, kind), label)
+        })
+        .collect()
+}
+
+/// Shifts a single byte offset from the old source to the new source across one `Edit`,
+/// mirroring how `tree_sitter::Tree::edit` adjusts node positions: offsets before the edit are
+/// untouched, offsets at or after its old end move by the same delta, and an offset that fell
+/// inside the edited range is clamped to the edit's start (it named a byte range that no longer
+/// exists as-is; any node actually keyed there is within a changed range anyway, so `node_is_changed`
+/// prevents the clamped key from ever being trusted).
+fn shift_byte(pos: usize, edit: &Edit) -> usize {
+    if pos <= edit.start_byte {
+        pos
+    } else if pos >= edit.old_end_byte {
+        (pos as isize + edit.new_end_byte as isize - edit.old_end_byte as isize) as usize
+    } else {
+        edit.start_byte
+    }
+}
+
 // Emit a slice of a source file as an Arg.
-fn sliced_source_arg(source: &Vec<u8>, n: Node) -> Arg {
+fn sliced_source_text<'a>(source: &'a Vec<u8>, n: Node) -> &'a str {
     let range = n.byte_range();
-    Arg::String(String::from(
-        std::str::from_utf8(&source[range.start..range.end]).expect("Failed to decode string"),
-    ))
+    std::str::from_utf8(&source[range.start..range.end]).expect("Failed to decode string")
+}
+
+/// The `label_cache` key for `node`: its byte range together with its kind, since a node's
+/// label must only be reused by another node of the same kind occupying the same range.
+fn node_key(node: &Node) -> (usize, usize, String) {
+    (node.start_byte(), node.end_byte(), node.kind().to_owned())
 }
 
 // Emit a 'Located' TrapEntry for the provided node, appropriately calibrated.
@@ -279,7 +688,7 @@ fn location_for<'a>(fp: &String, label: Label, n: Node) -> TrapEntry {
     let end_col = n.end_position().column;
     TrapEntry::Located(vec![
         Arg::Label(label),
-        Arg::String(fp.to_owned()),
+        Arg::String(Rc::from(fp.as_str())),
         Arg::Int(start_line),
         Arg::Int(start_col),
         Arg::Int(end_line),
@@ -287,18 +696,18 @@ fn location_for<'a>(fp: &String, label: Label, n: Node) -> TrapEntry {
     ])
 }
 
-fn traverse(tree: &Tree, visitor: &mut Visitor) {
+fn traverse(tree: &Tree, visitor: &mut Visitor) -> std::io::Result<()> {
     let cursor = &mut tree.walk();
-    visitor.enter_node(cursor.node());
+    visitor.enter_node(cursor.node())?;
     let mut recurse = true;
     loop {
         if recurse && cursor.goto_first_child() {
-            recurse = visitor.enter_node(cursor.node());
+            recurse = visitor.enter_node(cursor.node())?;
         } else {
-            visitor.leave_node(cursor.field_name(), cursor.node());
+            visitor.leave_node(cursor.field_name(), cursor.node())?;
 
             if cursor.goto_next_sibling() {
-                recurse = visitor.enter_node(cursor.node());
+                recurse = visitor.enter_node(cursor.node())?;
             } else if cursor.goto_parent() {
                 recurse = false;
             } else {
@@ -306,6 +715,156 @@ fn traverse(tree: &Tree, visitor: &mut Visitor) {
             }
         }
     }
+    Ok(())
+}
+
+/// Where `TrapEntry` rows produced by a `Visitor` go: buffered into a `Program` for
+/// `extract`/`extract_incremental`, or written out immediately for `extract_streaming`. Because
+/// a `ChildOf` row needs the parent label that is only known once the parent node closes, rows
+/// are only ever pushed once every value they reference is known: in `leave_node`/`complex_node`,
+/// and for trivia in `capture_trivia` (which references only the trivia node's own label) - so
+/// streaming them out as they're pushed needs no buffering beyond the `stack` and `trivia_stack`
+/// that already track each open node's pending children and trivia.
+enum TrapSink<'a> {
+    Buffer(Vec<TrapEntry>),
+    Stream(&'a mut dyn std::io::Write),
+}
+
+impl TrapSink<'_> {
+    fn push(&mut self, entry: TrapEntry) -> std::io::Result<()> {
+        match self {
+            TrapSink::Buffer(entries) => {
+                entries.push(entry);
+                Ok(())
+            }
+            TrapSink::Stream(out) => writeln!(out, "{}", entry),
+        }
+    }
+
+    fn into_buffer(self) -> Vec<TrapEntry> {
+        match self {
+            TrapSink::Buffer(entries) => entries,
+            TrapSink::Stream(_) => Vec::new(),
+        }
+    }
+}
+
+/// How severe a `Diagnostic` is. Recoverable problems (a type mismatch, an unknown field) are
+/// `Warning`s: extraction continues and simply drops the offending row. Problems that mean a
+/// node couldn't be understood at all (a parse error, an unknown table type) are `Error`s.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+impl Severity {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        }
+    }
+}
+
+/// What kind of problem a `Diagnostic` reports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiagnosticKind {
+    ParseError,
+    MissingNode,
+    UnknownTableType,
+    TypeMismatch,
+    UnknownField,
+    MissingFieldValue,
+    TooManyFieldValues,
+}
+
+impl DiagnosticKind {
+    fn severity(&self) -> Severity {
+        match self {
+            DiagnosticKind::ParseError
+            | DiagnosticKind::MissingNode
+            | DiagnosticKind::UnknownTableType
+            | DiagnosticKind::MissingFieldValue
+            | DiagnosticKind::TooManyFieldValues => Severity::Error,
+            DiagnosticKind::TypeMismatch | DiagnosticKind::UnknownField => Severity::Warning,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            DiagnosticKind::ParseError => "parse-error",
+            DiagnosticKind::MissingNode => "missing-node",
+            DiagnosticKind::UnknownTableType => "unknown-table-type",
+            DiagnosticKind::TypeMismatch => "type-mismatch",
+            DiagnosticKind::UnknownField => "unknown-field",
+            DiagnosticKind::MissingFieldValue => "missing-field-value",
+            DiagnosticKind::TooManyFieldValues => "too-many-field-values",
+        }
+    }
+}
+
+/// A problem noticed while extracting a file. Unlike the `println!`-based reporting this
+/// replaces, diagnostics are collected rather than printed, so extraction continues past
+/// recoverable errors and callers can consume them programmatically (see `diagnostics_to_json`)
+/// instead of having them interleaved with any stdout-bound TRAP output.
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub path: String,
+    pub row: usize,
+    pub kind: DiagnosticKind,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}: {}:{}: {}",
+            self.severity.as_str(),
+            self.path,
+            self.row,
+            self.message
+        )
+    }
+}
+
+fn json_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Renders diagnostics as a JSON array of `{severity, path, row, kind, message}` objects, for
+/// tooling that wants to consume them programmatically instead of the human-readable `Display`.
+pub fn diagnostics_to_json(diagnostics: &[Diagnostic]) -> String {
+    let mut out = String::from("[");
+    for (i, diagnostic) in diagnostics.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        out.push_str(&format!(
+            "{{\"severity\":\"{}\",\"path\":\"{}\",\"row\":{},\"kind\":\"{}\",\"message\":\"{}\"}}",
+            diagnostic.severity.as_str(),
+            json_escape(&diagnostic.path),
+            diagnostic.row,
+            diagnostic.kind.as_str(),
+            json_escape(&diagnostic.message),
+        ));
+    }
+    out.push(']');
+    out
 }
 
 pub struct Program(Vec<TrapEntry>);
@@ -329,6 +888,8 @@ enum TrapEntry {
     ChildOf(String, Label, String, Index, Label),
     // @location(loc, path, r1, c1, r2, c2)
     Located(Vec<Arg>),
+    // @comments_enclosing(comment, enclosing)@
+    TriviaOf(Label, Label),
     Comment(String),
 }
 impl fmt::Display for TrapEntry {
@@ -367,6 +928,9 @@ impl fmt::Display for TrapEntry {
                 args.get(4).unwrap(),
                 args.get(5).unwrap(),
             ),
+            TrapEntry::TriviaOf(comment, enclosing) => {
+                write!(f, "comments_enclosing({}, {})", comment, enclosing)
+            }
             TrapEntry::Comment(line) => write!(f, "// {}", line),
         }
     }
@@ -389,6 +953,16 @@ impl fmt::Display for Label {
     }
 }
 
+impl Label {
+    /// The `@location` label for the entity this label names, e.g. `#3` and `#3_loc` both
+    /// give back `#3_loc`.
+    fn location(self) -> Label {
+        match self {
+            Label::Normal(x) | Label::Location(x) => Label::Location(x),
+        }
+    }
+}
+
 // Numeric indices.
 #[derive(Debug, Copy, Clone)]
 struct Index(usize);
@@ -404,7 +978,7 @@ impl fmt::Display for Index {
 enum Arg {
     Label(Label),
     Int(usize),
-    String(String),
+    String(Rc<str>),
 }
 
 impl fmt::Display for Arg {
@@ -486,4 +1060,193 @@ fn node_type_name(kind: &str, named: bool) -> String {
     } else {
         format!("{}_unnamed", kind)
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interner_shares_one_allocation_per_distinct_string() {
+        let mut interner = StringInterner::new(true);
+        let first = interner.intern("foo");
+        let second = interner.intern("foo");
+        let other = interner.intern("bar");
+
+        // Same text comes back as the same allocation, shared rather than re-copied...
+        assert!(Rc::ptr_eq(&first, &second));
+        // ...but distinct text still gets its own allocation. Sharing the string is all
+        // the interner does: it must never be mistaken for a cache of the *label* a node
+        // with that text was assigned, since two occurrences of "foo" are still two
+        // distinct nodes with their own locations.
+        assert!(!Rc::ptr_eq(&first, &other));
+    }
+
+    #[test]
+    fn disabled_interner_never_caches_so_it_cannot_grow_unbounded() {
+        // extract_streaming disables the interner: every row is written out and dropped
+        // immediately, so there's no later occurrence to share an allocation with, and
+        // caching anyway would just grow with the file for no benefit.
+        let mut interner = StringInterner::new(false);
+        let first = interner.intern("foo");
+        let second = interner.intern("foo");
+
+        assert!(!Rc::ptr_eq(&first, &second));
+    }
+
+    #[test]
+    fn label_location_gives_back_the_loc_label_for_either_variant() {
+        assert_eq!(Label::Normal(5).location().to_string(), "#5_loc");
+        assert_eq!(Label::Location(5).location().to_string(), "#5_loc");
+    }
+
+    fn edit(start_byte: usize, old_end_byte: usize, new_end_byte: usize) -> Edit {
+        let zero = Point::new(0, 0);
+        Edit {
+            start_byte,
+            old_end_byte,
+            new_end_byte,
+            start_position: zero,
+            old_end_position: zero,
+            new_end_position: zero,
+        }
+    }
+
+    #[test]
+    fn shift_byte_is_unaffected_before_the_edit() {
+        let e = edit(10, 12, 15);
+        assert_eq!(shift_byte(0, &e), 0);
+        assert_eq!(shift_byte(10, &e), 10);
+    }
+
+    #[test]
+    fn shift_byte_moves_by_the_edit_delta_after_it() {
+        // Growing an edit (e.g. inserting text) pushes everything after it forward...
+        let growing = edit(10, 12, 15);
+        assert_eq!(shift_byte(12, &growing), 15);
+        assert_eq!(shift_byte(20, &growing), 23);
+
+        // ...shrinking one pulls everything after it back.
+        let shrinking = edit(10, 15, 12);
+        assert_eq!(shift_byte(15, &shrinking), 12);
+        assert_eq!(shift_byte(20, &shrinking), 17);
+    }
+
+    #[test]
+    fn shift_byte_clamps_positions_inside_the_edited_range() {
+        let e = edit(10, 20, 25);
+        assert_eq!(shift_byte(15, &e), 10);
+    }
+
+    #[test]
+    fn remap_label_cache_keeps_a_node_after_the_edit_point_findable() {
+        // An unchanged node spanning bytes 20..24 in the old source, after a 3-byte insertion
+        // at byte 5, should be looked up at bytes 23..27 in the new source - not its stale
+        // old range.
+        let mut labels = Map::new();
+        labels.insert((20, 24, "identifier".to_owned()), Label::Normal(3));
+
+        let remapped = remap_label_cache(labels, &[edit(5, 5, 8)]);
+
+        assert!(matches!(
+            remapped.get(&(23, 27, "identifier".to_owned())),
+            Some(Label::Normal(3))
+        ));
+    }
+
+    #[test]
+    fn remap_label_cache_composes_across_several_edits() {
+        // Two edits applied in the same `extract_incremental` call (as `for edit in edits`
+        // does to the tree) must shift the cache by both deltas, in order.
+        let mut labels = Map::new();
+        labels.insert((30, 34, "identifier".to_owned()), Label::Normal(7));
+
+        let remapped = remap_label_cache(labels, &[edit(0, 0, 2), edit(10, 10, 5)]);
+
+        // First edit: +2 after byte 0 -> (32, 36). Second edit: -5 after byte 10 -> (27, 31).
+        assert!(matches!(
+            remapped.get(&(27, 31, "identifier".to_owned())),
+            Some(Label::Normal(7))
+        ));
+    }
+
+    #[test]
+    fn trivia_of_renders_as_comments_enclosing_row() {
+        let entry = TrapEntry::TriviaOf(Label::Normal(1), Label::Normal(2));
+        assert_eq!(entry.to_string(), "comments_enclosing(#1, #2)");
+    }
+
+    #[test]
+    fn trap_sink_buffer_accumulates_entries_in_memory() {
+        let mut sink = TrapSink::Buffer(Vec::new());
+        sink.push(TrapEntry::Comment("a".to_owned())).unwrap();
+        sink.push(TrapEntry::Comment("b".to_owned())).unwrap();
+
+        let entries = sink.into_buffer();
+        assert_eq!(entries.len(), 2);
+    }
+
+    #[test]
+    fn trap_sink_stream_writes_each_entry_straight_through() {
+        let mut out = Vec::new();
+        {
+            let mut sink = TrapSink::Stream(&mut out);
+            sink.push(TrapEntry::Comment("hello".to_owned())).unwrap();
+        }
+
+        assert_eq!(String::from_utf8(out).unwrap(), "// hello\n");
+    }
+
+    #[test]
+    fn trap_sink_stream_into_buffer_is_empty() {
+        // A streaming sink never accumulates rows in memory - that's the whole point of
+        // extract_streaming's constant-memory guarantee - so into_buffer() has nothing to give.
+        let mut out = Vec::new();
+        let sink = TrapSink::Stream(&mut out);
+        assert!(sink.into_buffer().is_empty());
+    }
+
+    #[test]
+    fn diagnostic_kind_severity_matches_recoverability() {
+        // Errors mean the node couldn't be understood at all; warnings are recoverable and
+        // just drop the offending row.
+        assert_eq!(DiagnosticKind::ParseError.severity(), Severity::Error);
+        assert_eq!(DiagnosticKind::UnknownTableType.severity(), Severity::Error);
+        assert_eq!(DiagnosticKind::TypeMismatch.severity(), Severity::Warning);
+        assert_eq!(DiagnosticKind::UnknownField.severity(), Severity::Warning);
+    }
+
+    #[test]
+    fn diagnostic_display_is_one_line_human_readable() {
+        let diagnostic = Diagnostic {
+            severity: Severity::Warning,
+            path: "foo.rb".to_owned(),
+            row: 4,
+            kind: DiagnosticKind::UnknownField,
+            message: "value for unknown field: call::receiver".to_owned(),
+        };
+
+        assert_eq!(
+            diagnostic.to_string(),
+            "warning: foo.rb:4: value for unknown field: call::receiver"
+        );
+    }
+
+    #[test]
+    fn diagnostics_to_json_escapes_quotes_and_newlines_in_the_message() {
+        let diagnostics = vec![Diagnostic {
+            severity: Severity::Error,
+            path: "foo.rb".to_owned(),
+            row: 1,
+            kind: DiagnosticKind::ParseError,
+            message: "unexpected \"token\"\non this line".to_owned(),
+        }];
+
+        let json = diagnostics_to_json(&diagnostics);
+
+        assert_eq!(
+            json,
+            "[{\"severity\":\"error\",\"path\":\"foo.rb\",\"row\":1,\"kind\":\"parse-error\",\"message\":\"unexpected \\\"token\\\"\\non this line\"}]"
+        );
+    }
 }
\ No newline at end of file